@@ -1,16 +1,18 @@
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 
 use boringtun::crypto::{X25519PublicKey, X25519SecretKey};
 
+use ipnetwork::IpNetwork;
+
 use pyo3::exceptions::{PyKeyError, PyOSError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::{PyBytes, PyString, PyTuple};
 
-use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{self, channel, error::SendError, unbounded_channel};
 use tokio::sync::oneshot::{self, error::RecvError};
 use tokio::sync::Notify;
@@ -103,6 +105,69 @@ impl Drop for TcpStream {
     }
 }
 
+/// An individual UDP flow with an API similar to `asyncio.StreamReader`/`asyncio.StreamWriter`.
+#[pyclass]
+struct UdpStream {
+    connection_id: ConnectionId,
+    event_tx: mpsc::UnboundedSender<TransportCommand>,
+    peername: SocketAddr,
+    sockname: SocketAddr,
+    original_dst: SocketAddr,
+}
+
+#[pymethods]
+impl UdpStream {
+    /// Wait for and return the next datagram belonging to this flow.
+    fn read<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let (tx, rx) = oneshot::channel();
+        self.event_tx
+            .send(TransportCommand::ReadDatagram(self.connection_id, tx))
+            .map_err(event_queue_unavailable)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            let data = rx.await.map_err(connection_closed)?;
+            let bytes: Py<PyBytes> = Python::with_gil(|py| PyBytes::new(py, &data).into_py(py));
+            Ok(bytes)
+        })
+    }
+
+    fn write(&self, data: Vec<u8>) -> PyResult<()> {
+        self.event_tx
+            .send(TransportCommand::WriteDatagram(self.connection_id, data))
+            .map_err(event_queue_unavailable)?;
+        Ok(())
+    }
+
+    fn close(&self) -> PyResult<()> {
+        self.event_tx
+            .send(TransportCommand::CloseFlow(self.connection_id))
+            .map_err(event_queue_unavailable)?;
+        Ok(())
+    }
+
+    /// Supported values: peername, sockname, original_dst.
+    fn get_extra_info(&self, py: Python, name: String) -> PyResult<PyObject> {
+        match name.as_str() {
+            "peername" => Ok(socketaddr_to_py(py, self.peername)),
+            "sockname" => Ok(socketaddr_to_py(py, self.sockname)),
+            "original_dst" => Ok(socketaddr_to_py(py, self.original_dst)),
+            _ => Err(PyKeyError::new_err(name)),
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "UdpStream({}, peer={}, sock={}, dst={})",
+            self.connection_id, self.peername, self.sockname, self.original_dst,
+        )
+    }
+}
+
+impl Drop for UdpStream {
+    fn drop(&mut self) {
+        self.close().ok();
+    }
+}
+
 fn socketaddr_to_py(py: Python, s: SocketAddr) -> PyObject {
     match s {
         SocketAddr::V4(addr) => (addr.ip().to_string(), addr.port()).into_py(py),
@@ -138,10 +203,11 @@ fn connection_closed(_: RecvError) -> PyErr {
 #[pyclass]
 struct WireguardServer {
     event_tx: mpsc::UnboundedSender<TransportCommand>,
+    wg_event_tx: mpsc::UnboundedSender<TransportCommand>,
     local_addr: SocketAddr,
-    python_notify_task: JoinHandle<()>,
+    python_notify_task: Option<JoinHandle<()>>,
     wg_stopper: Arc<Notify>,
-    tcp_stopper: Arc<Notify>,
+    tcp_stopper: Option<Arc<Notify>>,
 }
 
 #[pymethods]
@@ -158,12 +224,82 @@ impl WireguardServer {
         Ok(())
     }
 
+    /// Add a WireGuard peer at runtime, routed by its `allowed_ips` CIDRs.
+    fn add_peer<'p>(
+        &self,
+        py: Python<'p>,
+        public_key: String,
+        preshared_key: Option<[u8; 32]>,
+        allowed_ips: Vec<String>,
+    ) -> PyResult<&'p PyAny> {
+        // validate eagerly, the same way init() parses the initial peer list, so that bad input is
+        // reported synchronously instead of silently no-op'ing inside the WireGuard task.
+        X25519PublicKey::from_str(&public_key).map_err(|error: &str| PyValueError::new_err(error.to_string()))?;
+        for cidr in &allowed_ips {
+            cidr.parse::<IpNetwork>()
+                .map_err(|_| PyValueError::new_err(format!("invalid allowed-IP CIDR: {}", cidr)))?;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.wg_event_tx
+            .send(TransportCommand::AddPeer {
+                public_key,
+                preshared_key,
+                allowed_ips,
+                result: tx,
+            })
+            .map_err(event_queue_unavailable)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            rx.await.map_err(connection_closed)?.map_err(PyValueError::new_err)?;
+            Ok(())
+        })
+    }
+
+    /// Remove the WireGuard peer with the given public key.
+    fn remove_peer<'p>(&self, py: Python<'p>, public_key: String) -> PyResult<&'p PyAny> {
+        let (tx, rx) = oneshot::channel();
+        self.wg_event_tx
+            .send(TransportCommand::RemovePeer { public_key, result: tx })
+            .map_err(event_queue_unavailable)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            rx.await.map_err(connection_closed)?.map_err(PyValueError::new_err)?;
+            Ok(())
+        })
+    }
+
+    /// Return the currently configured peers as `(public_key, allowed_ips)` tuples.
+    fn list_peers<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let (tx, rx) = oneshot::channel();
+        self.wg_event_tx
+            .send(TransportCommand::ListPeers(tx))
+            .map_err(event_queue_unavailable)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            let peers = rx.await.map_err(connection_closed)?;
+            Ok(peers)
+        })
+    }
+
+    /// Return live per-peer statistics as `(public_key, tx_bytes, rx_bytes, tx_packets, rx_packets, last_handshake, endpoint)` tuples,
+    /// where `last_handshake` is `None` until the first successful handshake and `endpoint` is the peer's current roaming address.
+    fn get_stats<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let (tx, rx) = oneshot::channel();
+        self.wg_event_tx
+            .send(TransportCommand::QueryStats(tx))
+            .map_err(event_queue_unavailable)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            let stats = rx.await.map_err(connection_closed)?;
+            Ok(stats)
+        })
+    }
+
     /// Terminate the WireGuard server.
     fn stop(&self) {
         self.wg_stopper.notify_one();
-        self.tcp_stopper.notify_one();
-
-        self.python_notify_task.abort();
+        if let Some(tcp_stopper) = &self.tcp_stopper {
+            tcp_stopper.notify_one();
+        }
+        if let Some(python_notify_task) = &self.python_notify_task {
+            python_notify_task.abort();
+        }
     }
 
     /// Get the local address the WireGuard server is listening on.
@@ -181,20 +317,28 @@ impl WireguardServer {
         host: String,
         port: u16,
         private_key: String,
-        peer_public_keys: Vec<(String, Option<[u8; 32]>)>,
-        py_tcp_handler: PyObject,
-        py_udp_handler: PyObject,
+        peer_public_keys: Vec<(String, Option<[u8; 32]>, Vec<String>)>,
+        py_tcp_handler: Option<PyObject>,
+        py_udp_handler: Option<PyObject>,
+        udp_flow_timeout: Duration,
+        gateway: bool,
+        transport: String,
+        py_peer_event_handler: Option<PyObject>,
     ) -> Result<WireguardServer> {
         let private_key: Arc<X25519SecretKey> = Arc::new(private_key.parse().map_err(|error: &str| anyhow!(error))?);
 
         // configure WireGuard peers
         let peers = peer_public_keys
             .into_iter()
-            .map(|(peer_public_key, preshared_key)| {
+            .map(|(peer_public_key, preshared_key, allowed_ips)| {
                 let key = Arc::new(X25519PublicKey::from_str(&peer_public_key).map_err(|error: &str| anyhow!(error))?);
-                Ok((key, preshared_key))
+                let allowed_ips = allowed_ips
+                    .iter()
+                    .map(|cidr| cidr.parse().map_err(|_| anyhow!("invalid allowed-IP CIDR: {}", cidr)))
+                    .collect::<Result<Vec<IpNetwork>>>()?;
+                Ok((key, preshared_key, allowed_ips))
             })
-            .collect::<Result<Vec<(Arc<X25519PublicKey>, Option<[u8; 32]>)>>>()?;
+            .collect::<Result<Vec<(Arc<X25519PublicKey>, Option<[u8; 32]>, Vec<IpNetwork>)>>>()?;
 
         let (py_loop, run_coroutine_threadsafe) = Python::with_gil(|py| -> PyResult<(PyObject, PyObject)> {
             Ok((
@@ -214,24 +358,58 @@ impl WireguardServer {
         // This channel needs to be unbounded because write() is not async.
         let (py_to_smol_tx, py_to_smol_rx) = unbounded_channel();
 
-        // bind to UDP socket
-        let socket = UdpSocket::bind((host, port)).await?;
-        let local_addr = socket.local_addr()?;
+        // bind the carrier for encrypted WireGuard frames
+        let transport: Box<dyn wireguard::Transport> = match transport.as_str() {
+            "udp" => Box::new(wireguard::UdpTransport::bind((host, port)).await?),
+            "websocket" => Box::new(wireguard::WebSocketTransport::bind((host, port)).await?),
+            other => return Err(anyhow!("unknown transport: {}", other)),
+        };
+        let local_addr = transport.local_addr()?;
 
         // initialize WireGuard server
+        // channel carrying runtime peer-management commands to the WireGuard server
+        let (wg_event_tx, wg_event_rx) = unbounded_channel();
+
         let mut wg_server_builder = wireguard::WireguardServerBuilder::new(private_key, wg_to_smol_tx, smol_to_wg_rx);
-        for (peer_public_key, preshared_key) in peers {
-            wg_server_builder.add_peer(peer_public_key, preshared_key)?;
+        for (peer_public_key, preshared_key, allowed_ips) in peers {
+            wg_server_builder.add_peer(peer_public_key, preshared_key, allowed_ips)?;
+        }
+        // forward handshake/rekey/eviction events to the python interop task when a callback is set
+        if py_peer_event_handler.is_some() {
+            wg_server_builder.on_peer_event(smol_to_py_tx.clone());
         }
         let wg_server = wg_server_builder.build()?;
         let wg_stopper = wg_server.stopper();
 
+        // In gateway mode we bypass the Python interception layer entirely and bridge the
+        // decrypted packets to a real OS TUN interface, so the crate acts as a WireGuard router.
+        // NOTE: spawn the fallible bridge/device setup *before* the WireGuard task, so that an early
+        // error (e.g. no TUN access) doesn't leak a running task bound to the listening transport.
+        if gateway {
+            let _tun_handle = spawn_tun_gateway(wg_to_smol_rx, smol_to_wg_tx)?;
+            let _wg_handle = tokio::spawn(async move { wg_server.run(transport, wg_event_rx).await });
+            return Ok(WireguardServer {
+                event_tx: py_to_smol_tx,
+                wg_event_tx,
+                local_addr,
+                python_notify_task: None,
+                wg_stopper,
+                tcp_stopper: None,
+            });
+        }
+
         // initialize virtual network device
-        let tcp_server = tcp::TcpServer::new(smol_to_wg_tx, wg_to_smol_rx, smol_to_py_tx, py_to_smol_rx)?;
+        let tcp_server = tcp::TcpServer::new(
+            smol_to_wg_tx,
+            wg_to_smol_rx,
+            smol_to_py_tx,
+            py_to_smol_rx,
+            udp_flow_timeout,
+        )?;
         let tcp_stopper = tcp_server.stopper();
 
-        // spawn tasks
-        let _wg_handle = tokio::spawn(async move { wg_server.run(socket).await });
+        // spawn the WireGuard server now that the fallible setup above has succeeded
+        let _wg_handle = tokio::spawn(async move { wg_server.run(transport, wg_event_rx).await });
         let _tcp_handle = tokio::spawn(async move { tcp_server.run().await });
 
         let event_tx = py_to_smol_tx.clone();
@@ -251,40 +429,62 @@ impl WireguardServer {
                             sockname: local_addr,
                             original_dst: dst_addr,
                         };
-                        Python::with_gil(|py| {
-                            let stream = stream.into_py(py);
-                            let coro = match py_tcp_handler.call1(py, (stream.clone_ref(py), stream)) {
-                                Ok(coro) => coro,
-                                Err(err) => {
+                        if let Some(handler) = &py_tcp_handler {
+                            Python::with_gil(|py| {
+                                let stream = stream.into_py(py);
+                                let coro = match handler.call1(py, (stream.clone_ref(py), stream)) {
+                                    Ok(coro) => coro,
+                                    Err(err) => {
+                                        err.print(py);
+                                        return;
+                                    },
+                                };
+                                if let Err(err) = run_coroutine_threadsafe.call1(py, (coro, py_loop.as_ref(py))) {
                                     err.print(py);
-                                    return;
-                                },
-                            };
-                            if let Err(err) = run_coroutine_threadsafe.call1(py, (coro, py_loop.as_ref(py))) {
-                                err.print(py);
-                            }
-                        });
+                                }
+                            });
+                        }
                     },
-                    TransportEvent::DatagramReceived {
-                        data,
+                    TransportEvent::UdpFlowEstablished {
+                        connection_id,
                         src_addr,
                         dst_addr,
                     } => {
-                        Python::with_gil(|py| {
-                            let bytes: Py<PyBytes> = PyBytes::new(py, &data).into_py(py);
-                            if let Err(err) = py_loop.call_method1(
-                                py,
-                                "call_soon_threadsafe",
-                                (
-                                    py_udp_handler.as_ref(py),
-                                    bytes,
-                                    socketaddr_to_py(py, src_addr),
-                                    socketaddr_to_py(py, dst_addr),
-                                ),
-                            ) {
-                                err.print(py);
-                            }
-                        });
+                        let stream = UdpStream {
+                            connection_id,
+                            event_tx: event_tx.clone(),
+                            peername: src_addr,
+                            sockname: local_addr,
+                            original_dst: dst_addr,
+                        };
+                        if let Some(handler) = &py_udp_handler {
+                            Python::with_gil(|py| {
+                                let stream = stream.into_py(py);
+                                let coro = match handler.call1(py, (stream.clone_ref(py), stream)) {
+                                    Ok(coro) => coro,
+                                    Err(err) => {
+                                        err.print(py);
+                                        return;
+                                    },
+                                };
+                                if let Err(err) = run_coroutine_threadsafe.call1(py, (coro, py_loop.as_ref(py))) {
+                                    err.print(py);
+                                }
+                            });
+                        }
+                    },
+                    TransportEvent::PeerEvent { public_key, kind } => {
+                        if let Some(handler) = &py_peer_event_handler {
+                            Python::with_gil(|py| {
+                                if let Err(err) = py_loop.call_method1(
+                                    py,
+                                    "call_soon_threadsafe",
+                                    (handler.as_ref(py), public_key, kind.to_string()),
+                                ) {
+                                    err.print(py);
+                                }
+                            });
+                        }
                     },
                 }
             }
@@ -292,14 +492,76 @@ impl WireguardServer {
 
         Ok(WireguardServer {
             event_tx: py_to_smol_tx,
+            wg_event_tx,
             local_addr,
-            python_notify_task,
+            python_notify_task: Some(python_notify_task),
             wg_stopper,
-            tcp_stopper,
+            tcp_stopper: Some(tcp_stopper),
         })
     }
 }
 
+/// Bridge the WireGuard server's packet channels to a real OS TUN interface for transparent
+/// forwarding; in gateway mode the TUN takes the place of the virtual network device.
+///
+/// Outbound IP packets produced by the WireGuard server are written to the TUN `fd`, and packets
+/// read back from the TUN are fed into the server's injection channel for encryption.
+#[cfg(target_os = "linux")]
+fn spawn_tun_gateway(
+    mut wg_to_smol_rx: mpsc::Receiver<Vec<u8>>,
+    smol_to_wg_tx: mpsc::Sender<Vec<u8>>,
+) -> Result<JoinHandle<()>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let tun = tokio_tun::TunBuilder::new()
+        .name("")
+        .tap(false)
+        .packet_info(false)
+        .up()
+        .try_build()?;
+    log::info!("gateway mode: attached TUN interface {}", tun.name());
+
+    let (mut reader, mut writer) = tokio::io::split(tun);
+
+    // WireGuard server -> TUN
+    tokio::spawn(async move {
+        while let Some(packet) = wg_to_smol_rx.recv().await {
+            if let Err(err) = writer.write_all(&packet).await {
+                log::warn!("failed to write packet to TUN: {}", err);
+                break;
+            }
+        }
+    });
+
+    // TUN -> WireGuard server
+    Ok(tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if smol_to_wg_tx.send(buf[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                },
+                Err(err) => {
+                    log::warn!("failed to read packet from TUN: {}", err);
+                    break;
+                },
+            }
+        }
+    }))
+}
+
+/// TUN gateway mode is only available on Linux; on other platforms it fails with a clear error.
+#[cfg(not(target_os = "linux"))]
+fn spawn_tun_gateway(
+    _wg_to_smol_rx: mpsc::Receiver<Vec<u8>>,
+    _smol_to_wg_tx: mpsc::Sender<Vec<u8>>,
+) -> Result<JoinHandle<()>> {
+    Err(anyhow!("gateway mode is only supported on Linux"))
+}
+
 impl Drop for WireguardServer {
     fn drop(&mut self) {
         self.stop();
@@ -308,15 +570,39 @@ impl Drop for WireguardServer {
 
 /// Start a WireGuard server.
 #[pyfunction]
+#[pyo3(signature = (
+    host,
+    port,
+    private_key,
+    peer_public_keys,
+    handle_connection,
+    handle_udp_flow,
+    udp_flow_timeout_secs = 60,
+    gateway = false,
+    transport = "udp".to_string(),
+    on_peer_event = None,
+))]
 fn start_server(
     py: Python<'_>,
     host: String,
     port: u16,
     private_key: String,
-    peer_public_keys: Vec<(String, Option<[u8; 32]>)>,
-    handle_connection: PyObject,
-    receive_datagram: PyObject,
+    peer_public_keys: Vec<(String, Option<[u8; 32]>, Vec<String>)>,
+    handle_connection: Option<PyObject>,
+    handle_udp_flow: Option<PyObject>,
+    udp_flow_timeout_secs: u64,
+    gateway: bool,
+    transport: String,
+    on_peer_event: Option<PyObject>,
 ) -> PyResult<&PyAny> {
+    if gateway && !cfg!(target_os = "linux") {
+        return Err(PyOSError::new_err("gateway mode is only supported on Linux"));
+    }
+    if gateway && on_peer_event.is_some() {
+        return Err(PyValueError::new_err(
+            "on_peer_event is not supported in gateway mode",
+        ));
+    }
     pyo3_asyncio::tokio::future_into_py(py, async move {
         // XXX: This is a bit of a race condition: the  handler could be called before
         // .server = await start_server() has assigned to .server.
@@ -326,7 +612,11 @@ fn start_server(
             private_key,
             peer_public_keys,
             handle_connection,
-            receive_datagram,
+            handle_udp_flow,
+            Duration::from_secs(udp_flow_timeout_secs),
+            gateway,
+            transport,
+            on_peer_event,
         )
         .await?;
         Ok(server)
@@ -362,5 +652,6 @@ fn mitmproxy_wireguard(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(pubkey, m)?)?;
     m.add_class::<WireguardServer>()?;
     m.add_class::<TcpStream>()?;
+    m.add_class::<UdpStream>()?;
     Ok(())
 }